@@ -1,6 +1,9 @@
-use serde_json::value::{Number, Value as SerdeValue};
+use regex::Regex;
+use serde_json::value::Number;
 use std::fmt::Debug;
 
+use crate::jsontokenizer::{JsonTokenizer, Token, TokenKind, TokenizeError};
+
 type Index = usize;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -22,7 +25,7 @@ impl From<usize> for OptionIndex {
 }
 
 #[derive(Debug)]
-pub struct FlatJson(Vec<Row>);
+pub struct FlatJson(Vec<Row>, String);
 
 #[derive(Debug)]
 pub struct Row {
@@ -33,19 +36,23 @@ pub struct Row {
 
     depth: usize,
     index: Index,
-    // start_index: usize
-    // end_index: usize
+    // The position of this row among its siblings as originally parsed.
+    // Unlike `index`, this is never touched by `FlatJson::sort`, so sorting
+    // back to `SortOrder::Original` is just sorting by this field again.
+    original_index: Index,
+    start_index: usize,
+    end_index: usize,
     key: Option<String>,
     value: Value,
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 enum ContainerType {
     Object,
     Array,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 enum Value {
     Null,
     Boolean(bool),
@@ -65,17 +72,38 @@ enum Value {
     },
 }
 
-pub fn parse_top_level_json(json: String) -> serde_json::Result<FlatJson> {
-    let serde_value = serde_json::from_str(&json)?;
+// Drives `FlatJson` construction directly off `JsonTokenizer`, one token at a
+// time, instead of first parsing into a `serde_json::Value` tree.
+pub fn parse_top_level_json(json: String) -> Result<FlatJson, TokenizeError> {
+    let mut tokenizer = JsonTokenizer::new(&json);
     let mut flat_json = vec![];
     let mut parents = vec![OptionIndex::Nil];
 
-    flatten_json(serde_value, &mut flat_json, &mut parents);
+    let first_token = tokenizer.next_token()?.ok_or_else(|| TokenizeError {
+        message: "unexpected end of input".to_owned(),
+        index: 0,
+    })?;
+
+    parse_value(&json, first_token, &mut tokenizer, &mut flat_json, &mut parents)?;
+
+    // Reject trailing data after the top-level value, e.g. `{}garbage`.
+    if tokenizer.next_token()?.is_some() {
+        return Err(TokenizeError {
+            message: "trailing data after top-level value".to_owned(),
+            index: flat_json.last().map(|row| row.end_index).unwrap_or(0),
+        });
+    }
 
-    Ok(FlatJson(flat_json))
+    Ok(FlatJson(flat_json, json))
 }
 
-fn flatten_json(serde_value: SerdeValue, flat_json: &mut Vec<Row>, parents: &mut Vec<OptionIndex>) {
+fn parse_value(
+    json: &str,
+    token: Token,
+    tokenizer: &mut JsonTokenizer,
+    flat_json: &mut Vec<Row>,
+    parents: &mut Vec<OptionIndex>,
+) -> Result<(), TokenizeError> {
     let depth = parents.len() - 1;
     let parent = *parents.last().unwrap();
 
@@ -85,77 +113,173 @@ fn flatten_json(serde_value: SerdeValue, flat_json: &mut Vec<Row>, parents: &mut
         next_sibling: OptionIndex::Nil,
         depth,
         index: 0,
+        original_index: 0,
+        start_index: token.start,
+        end_index: token.end,
         key: None,
         value: Value::Null,
     };
 
-    match serde_value {
-        SerdeValue::Null => flat_json.push(row),
-        SerdeValue::Bool(b) => flat_json.push(Row {
-            value: Value::Boolean(b),
+    match token.kind {
+        TokenKind::Null => flat_json.push(row),
+        TokenKind::True => flat_json.push(Row {
+            value: Value::Boolean(true),
             ..row
         }),
-        SerdeValue::Number(n) => flat_json.push(Row {
-            value: Value::Number(n),
+        TokenKind::False => flat_json.push(Row {
+            value: Value::Boolean(false),
             ..row
         }),
-        SerdeValue::String(s) => flat_json.push(Row {
+        TokenKind::Number => {
+            let text = &json[token.start..token.end];
+            let number: Number = serde_json::from_str(text).map_err(|_| TokenizeError {
+                message: "invalid number".to_owned(),
+                index: token.start,
+            })?;
+            flat_json.push(Row {
+                value: Value::Number(number),
+                ..row
+            });
+        }
+        TokenKind::String(s) => flat_json.push(Row {
             value: Value::String(s),
             ..row
         }),
-        SerdeValue::Array(vs) => {
-            if vs.len() == 0 {
-                flat_json.push(Row {
-                    value: Value::EmptyArray,
-                    ..row
-                })
-            } else {
-                let open_index = flat_json.len();
-                parents.push(OptionIndex::Index(open_index));
+        TokenKind::ArrayOpen => {
+            parse_container(json, ContainerType::Array, token, tokenizer, flat_json, parents)?;
+        }
+        TokenKind::ObjectOpen => {
+            parse_container(json, ContainerType::Object, token, tokenizer, flat_json, parents)?;
+        }
+        _ => {
+            return Err(TokenizeError {
+                message: "expected a value".to_owned(),
+                index: token.start,
+            })
+        }
+    }
 
-                flat_json.push(Row {
-                    value: Value::OpenContainer {
-                        container_type: ContainerType::Array,
-                        first_child: open_index + 1,
-                        // Set once done processing the array.
-                        close_index: 0,
-                    },
-                    ..row
-                });
+    Ok(())
+}
 
-                let mut prev_sibling: OptionIndex = OptionIndex::Nil;
-                let mut child_index = 0;
+fn parse_container(
+    json: &str,
+    container_type: ContainerType,
+    open_token: Token,
+    tokenizer: &mut JsonTokenizer,
+    flat_json: &mut Vec<Row>,
+    parents: &mut Vec<OptionIndex>,
+) -> Result<(), TokenizeError> {
+    let depth = parents.len() - 1;
+    let parent = *parents.last().unwrap();
 
-                for (i, v) in vs.into_iter().enumerate() {
-                    child_index = flat_json.len();
+    let (close_kind, empty_value) = match container_type {
+        ContainerType::Array => (TokenKind::ArrayClose, Value::EmptyArray),
+        ContainerType::Object => (TokenKind::ObjectClose, Value::EmptyObject),
+    };
 
-                    flatten_json(v, flat_json, parents);
-                    let mut child = &mut flat_json[child_index];
+    let next = next_token(tokenizer)?;
+    if next.kind == close_kind {
+        flat_json.push(Row {
+            parent,
+            prev_sibling: OptionIndex::Nil,
+            next_sibling: OptionIndex::Nil,
+            depth,
+            index: 0,
+            original_index: 0,
+            start_index: open_token.start,
+            end_index: next.end,
+            key: None,
+            value: empty_value,
+        });
+        return Ok(());
+    }
 
-                    child.index = i;
-                    child.prev_sibling = prev_sibling;
+    let open_index = flat_json.len();
+    parents.push(OptionIndex::Index(open_index));
 
-                    if let OptionIndex::Index(prev_sibling_index) = prev_sibling {
-                        flat_json[prev_sibling_index].next_sibling =
-                            OptionIndex::Index(child_index);
+    flat_json.push(Row {
+        parent,
+        prev_sibling: OptionIndex::Nil,
+        next_sibling: OptionIndex::Nil,
+        depth,
+        index: 0,
+        original_index: 0,
+        start_index: open_token.start,
+        end_index: open_token.end,
+        key: None,
+        value: Value::OpenContainer {
+            container_type,
+            first_child: open_index + 1,
+            // Set once done processing the container.
+            close_index: 0,
+        },
+    });
+
+    let mut prev_sibling: OptionIndex = OptionIndex::Nil;
+    let mut child_index;
+    let mut next_token_for_value = Some(next);
+    let mut i = 0;
+
+    loop {
+        let value_token = match next_token_for_value.take() {
+            Some(t) => t,
+            None => next_token(tokenizer)?,
+        };
+
+        child_index = flat_json.len();
+
+        let (key, value_start_token) = match container_type {
+            ContainerType::Object => {
+                let key_start = value_token.start;
+                let key = match value_token.kind {
+                    TokenKind::String(s) => s,
+                    _ => {
+                        return Err(TokenizeError {
+                            message: "expected object key".to_owned(),
+                            index: key_start,
+                        })
                     }
+                };
+                expect_kind(tokenizer, TokenKind::Colon)?;
+                (Some(key), next_token(tokenizer)?)
+            }
+            ContainerType::Array => (None, value_token),
+        };
 
-                    prev_sibling = OptionIndex::Index(child_index);
-                }
+        parse_value(json, value_start_token, tokenizer, flat_json, parents)?;
 
+        let child = &mut flat_json[child_index];
+        child.index = i;
+        child.original_index = i;
+        child.prev_sibling = prev_sibling;
+        child.key = key;
+
+        if let OptionIndex::Index(prev_sibling_index) = prev_sibling {
+            flat_json[prev_sibling_index].next_sibling = OptionIndex::Index(child_index);
+        }
+
+        prev_sibling = OptionIndex::Index(child_index);
+        i += 1;
+
+        let separator = next_token(tokenizer)?;
+        match separator.kind {
+            TokenKind::Comma => continue,
+            kind if kind == close_kind => {
                 let close_index = flat_json.len();
                 flat_json.push(Row {
                     parent,
-                    // Currently not set on the CloseContainer value.
                     prev_sibling: OptionIndex::Nil,
                     next_sibling: OptionIndex::Nil,
                     depth,
                     index: 0,
+                    original_index: 0,
+                    start_index: separator.start,
+                    end_index: separator.end,
                     key: None,
                     value: Value::CloseContainer {
-                        container_type: ContainerType::Array,
+                        container_type,
                         last_child: child_index,
-                        // Set once done processing the array.
                         open_index,
                     },
                 });
@@ -168,78 +292,778 @@ fn flatten_json(serde_value: SerdeValue, flat_json: &mut Vec<Row>, parents: &mut
                     *close_index_of_open_value = close_index;
                 }
 
+                flat_json[open_index].end_index = separator.end;
                 parents.pop();
+                break;
             }
-        }
-        SerdeValue::Object(obj) => {
-            if obj.len() == 0 {
-                flat_json.push(Row {
-                    value: Value::EmptyObject,
-                    ..row
+            _ => {
+                return Err(TokenizeError {
+                    message: "expected `,` or closing bracket".to_owned(),
+                    index: separator.start,
                 })
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn next_token(tokenizer: &mut JsonTokenizer) -> Result<Token, TokenizeError> {
+    tokenizer.next_token()?.ok_or_else(|| TokenizeError {
+        message: "unexpected end of input".to_owned(),
+        index: 0,
+    })
+}
+
+fn expect_kind(tokenizer: &mut JsonTokenizer, kind: TokenKind) -> Result<Token, TokenizeError> {
+    let token = next_token(tokenizer)?;
+    if token.kind == kind {
+        Ok(token)
+    } else {
+        Err(TokenizeError {
+            message: format!("expected {:?}", kind),
+            index: token.start,
+        })
+    }
+}
+
+impl FlatJson {
+    // Returns the exact original source text `index`'s row was parsed from --
+    // including whitespace and number formatting -- rather than a
+    // re-serialized `Row::value`, which would lose both. Backs the viewer's
+    // yank command (see `viewer::yank_raw_source`).
+    //
+    // A `FlatJson` produced by `merge` has no single source string to slice
+    // (its rows are copied from two different documents), so this always
+    // returns an empty string for one; it's only meaningful for a `FlatJson`
+    // from `parse_top_level_json`.
+    pub fn raw_source(&self, index: usize) -> &str {
+        let row = &self.0[index];
+        self.1.get(row.start_index..row.end_index).unwrap_or("")
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+    // Restores the order the container's children appeared in the source.
+    Original,
+}
+
+impl FlatJson {
+    // Recursively sorts the children of `container` (objects by key, arrays
+    // by their scalar values) and every nested container beneath it, without
+    // moving any `Row` in the backing `Vec`. Instead the sibling chain
+    // (`prev_sibling`/`next_sibling`), the container's `first_child`, its
+    // `CloseContainer`'s `last_child`, and each child's `index` are rewritten
+    // to reflect the new order.
+    pub fn sort(&mut self, container: usize, order: SortOrder) {
+        let close_index = match &self.0[container].value {
+            Value::OpenContainer { close_index, .. } => *close_index,
+            _ => return,
+        };
+
+        let mut children = self.children_of(container);
+
+        for &child in &children {
+            if matches!(self.0[child].value, Value::OpenContainer { .. }) {
+                self.sort(child, order);
+            }
+        }
+
+        match order {
+            SortOrder::Original => children.sort_by_key(|&i| self.0[i].original_index),
+            SortOrder::Ascending => {
+                children.sort_by(|&a, &b| compare_rows(&self.0[a], &self.0[b]));
+            }
+            SortOrder::Descending => {
+                children.sort_by(|&a, &b| compare_rows(&self.0[b], &self.0[a]));
+            }
+        }
+
+        self.relink_children(container, close_index, &children);
+    }
+
+    fn relink_children(&mut self, container: usize, close_index: usize, children: &[Index]) {
+        for (i, &child) in children.iter().enumerate() {
+            self.0[child].index = i;
+            self.0[child].prev_sibling = if i == 0 {
+                OptionIndex::Nil
             } else {
-                let open_index = flat_json.len();
-                parents.push(OptionIndex::Index(open_index));
+                OptionIndex::Index(children[i - 1])
+            };
+            self.0[child].next_sibling = match children.get(i + 1) {
+                Some(&next) => OptionIndex::Index(next),
+                None => OptionIndex::Nil,
+            };
+        }
 
-                flat_json.push(Row {
-                    value: Value::OpenContainer {
-                        container_type: ContainerType::Object,
-                        first_child: open_index + 1,
-                        // Set once done processing the array.
-                        close_index: 0,
-                    },
-                    ..row
-                });
+        if let Value::OpenContainer {
+            first_child: ref mut fc,
+            ..
+        } = &mut self.0[container].value
+        {
+            *fc = children[0];
+        }
+
+        if let Value::CloseContainer {
+            last_child: ref mut lc,
+            ..
+        } = &mut self.0[close_index].value
+        {
+            *lc = *children.last().unwrap();
+        }
+    }
+
+    // Collects the indexes of `container`'s direct children by walking
+    // `first_child` along `next_sibling`. Returns an empty `Vec` if `container`
+    // isn't an open container (e.g. it's empty, or a scalar).
+    fn children_of(&self, container: Index) -> Vec<Index> {
+        let first_child = match &self.0[container].value {
+            Value::OpenContainer { first_child, .. } => *first_child,
+            _ => return vec![],
+        };
+
+        let mut children = vec![];
+        let mut next = OptionIndex::Index(first_child);
+        while let OptionIndex::Index(child) = next {
+            children.push(child);
+            next = self.0[child].next_sibling;
+        }
+        children
+    }
+}
+
+// Orders object children by key and array children by scalar value; nested
+// containers rank after scalars and compare equal to each other, which
+// (combined with the stable sort above) keeps them in their current order.
+fn compare_rows(a: &Row, b: &Row) -> std::cmp::Ordering {
+    if let (Some(a_key), Some(b_key)) = (&a.key, &b.key) {
+        return a_key.cmp(b_key);
+    }
+
+    value_rank(&a.value)
+        .cmp(&value_rank(&b.value))
+        .then_with(|| compare_scalars(&a.value, &b.value))
+}
+
+fn value_rank(value: &Value) -> u8 {
+    match value {
+        Value::Null => 0,
+        Value::Boolean(_) => 1,
+        Value::Number(_) => 2,
+        Value::String(_) => 3,
+        _ => 4,
+    }
+}
+
+fn compare_scalars(a: &Value, b: &Value) -> std::cmp::Ordering {
+    match (a, b) {
+        (Value::Boolean(a), Value::Boolean(b)) => a.cmp(b),
+        (Value::Number(a), Value::Number(b)) => a
+            .as_f64()
+            .unwrap_or(0.0)
+            .partial_cmp(&b.as_f64().unwrap_or(0.0))
+            .unwrap_or(std::cmp::Ordering::Equal),
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        _ => std::cmp::Ordering::Equal,
+    }
+}
+
+// Whether elements at the same array index are compared against each other,
+// or the two arrays are simply shown back-to-back (left's elements, then
+// right's), each tagged as if it only existed on its own side.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ArrayMergeMode {
+    Aligned,
+    Concatenated,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MergeStatus {
+    Unchanged,
+    Added,
+    Removed,
+    Modified,
+}
+
+impl FlatJson {
+    // Builds a single `FlatJson` representing the deep merge of `left` and
+    // `right`, with every row tagged in the returned `Vec<MergeStatus>`
+    // (indexed the same way as the `FlatJson`'s rows) so callers like
+    // `lineprinter`/`highlighting` can color the tree like a diff. Objects
+    // are unioned key-by-key; keys present on only one side are `Added` or
+    // `Removed`; keys present on both recurse if both sides are containers
+    // of the same type, otherwise the right-hand value is shown and the pair
+    // is `Modified` (or `Unchanged` for two equal scalars).
+    pub fn merge(left: &FlatJson, right: &FlatJson, array_mode: ArrayMergeMode) -> (FlatJson, Vec<MergeStatus>) {
+        let mut rows = vec![];
+        let mut statuses = vec![];
+        let mut parents = vec![OptionIndex::Nil];
+
+        merge_node(
+            Some((left, 0)),
+            Some((right, 0)),
+            &mut rows,
+            &mut statuses,
+            &mut parents,
+            array_mode,
+        );
+
+        (FlatJson(rows, String::new()), statuses)
+    }
+}
+
+fn container_kind(value: &Value) -> Option<ContainerType> {
+    match value {
+        Value::OpenContainer { container_type, .. } => Some(*container_type),
+        Value::EmptyObject => Some(ContainerType::Object),
+        Value::EmptyArray => Some(ContainerType::Array),
+        _ => None,
+    }
+}
 
-                let mut prev_sibling: OptionIndex = OptionIndex::Nil;
-                let mut child_index = 0;
+fn merge_node<'a>(
+    left: Option<(&'a FlatJson, Index)>,
+    right: Option<(&'a FlatJson, Index)>,
+    rows: &mut Vec<Row>,
+    statuses: &mut Vec<MergeStatus>,
+    parents: &mut Vec<OptionIndex>,
+    array_mode: ArrayMergeMode,
+) -> MergeStatus {
+    match (left, right) {
+        (Some((fj, idx)), None) => copy_subtree(fj, idx, rows, statuses, parents, MergeStatus::Removed),
+        (None, Some((fj, idx))) => copy_subtree(fj, idx, rows, statuses, parents, MergeStatus::Added),
+        (Some((l_fj, l_idx)), Some((r_fj, r_idx))) => {
+            merge_both((l_fj, l_idx), (r_fj, r_idx), rows, statuses, parents, array_mode)
+        }
+        (None, None) => unreachable!("merge_node needs at least one side present"),
+    }
+}
 
-                for (i, (k, v)) in obj.into_iter().enumerate() {
-                    child_index = flat_json.len();
+// Copies a whole subtree from one side unchanged, tagging every row (root and
+// descendants alike) with `status`. Used for keys/elements that only exist
+// on one side of the merge.
+fn copy_subtree(
+    fj: &FlatJson,
+    idx: Index,
+    rows: &mut Vec<Row>,
+    statuses: &mut Vec<MergeStatus>,
+    parents: &mut Vec<OptionIndex>,
+    status: MergeStatus,
+) -> MergeStatus {
+    let depth = parents.len() - 1;
+    let parent = *parents.last().unwrap();
+    let src = &fj.0[idx];
 
-                    flatten_json(v, flat_json, parents);
-                    let mut child = &mut flat_json[child_index];
+    let row_index = rows.len();
 
-                    child.index = i;
-                    child.prev_sibling = prev_sibling;
-                    child.key = Some(k);
+    match container_kind(&src.value) {
+        None => {
+            rows.push(Row {
+                parent,
+                prev_sibling: OptionIndex::Nil,
+                next_sibling: OptionIndex::Nil,
+                depth,
+                index: 0,
+                original_index: 0,
+                start_index: src.start_index,
+                end_index: src.end_index,
+                key: None,
+                value: src.value.clone(),
+            });
+            statuses.push(status);
+        }
+        Some(container_type) => {
+            let children = fj.children_of(idx);
 
-                    if let OptionIndex::Index(prev_sibling_index) = prev_sibling {
-                        flat_json[prev_sibling_index].next_sibling =
-                            OptionIndex::Index(child_index);
+            rows.push(Row {
+                parent,
+                prev_sibling: OptionIndex::Nil,
+                next_sibling: OptionIndex::Nil,
+                depth,
+                index: 0,
+                original_index: 0,
+                start_index: src.start_index,
+                end_index: src.end_index,
+                key: None,
+                value: if children.is_empty() {
+                    src.value.clone()
+                } else {
+                    Value::OpenContainer {
+                        container_type,
+                        first_child: row_index + 1,
+                        close_index: 0,
                     }
+                },
+            });
+            statuses.push(status);
+
+            if !children.is_empty() {
+                parents.push(OptionIndex::Index(row_index));
+
+                let mut prev_sibling = OptionIndex::Nil;
+                let mut last_child = row_index;
+
+                for (i, &child) in children.iter().enumerate() {
+                    let child_index = rows.len();
+                    copy_subtree(fj, child, rows, statuses, parents, status);
 
+                    rows[child_index].index = i;
+                    rows[child_index].original_index = i;
+                    rows[child_index].key = fj.0[child].key.clone();
+                    rows[child_index].prev_sibling = prev_sibling;
+                    if let OptionIndex::Index(p) = prev_sibling {
+                        rows[p].next_sibling = OptionIndex::Index(child_index);
+                    }
                     prev_sibling = OptionIndex::Index(child_index);
+                    last_child = child_index;
                 }
 
-                let close_index = flat_json.len();
-                flat_json.push(Row {
+                parents.pop();
+
+                let close_index = rows.len();
+                rows.push(Row {
                     parent,
-                    // Currently not set on the CloseContainer value.
                     prev_sibling: OptionIndex::Nil,
                     next_sibling: OptionIndex::Nil,
                     depth,
                     index: 0,
+                    original_index: 0,
+                    start_index: src.end_index,
+                    end_index: src.end_index,
                     key: None,
                     value: Value::CloseContainer {
-                        container_type: ContainerType::Object,
-                        last_child: child_index,
-                        // Set once done processing the array.
-                        open_index,
+                        container_type,
+                        last_child,
+                        open_index: row_index,
                     },
                 });
+                statuses.push(status);
 
                 if let Value::OpenContainer {
-                    close_index: ref mut close_index_of_open_value,
+                    close_index: ref mut ci,
                     ..
-                } = &mut flat_json[open_index].value
+                } = &mut rows[row_index].value
                 {
-                    *close_index_of_open_value = close_index;
+                    *ci = close_index;
                 }
+            }
+        }
+    }
 
-                parents.pop();
+    status
+}
+
+type MergeItem<'a> = (
+    Option<String>,
+    Option<(&'a FlatJson, Index)>,
+    Option<(&'a FlatJson, Index)>,
+);
+
+fn merge_both<'a>(
+    (l_fj, l_idx): (&'a FlatJson, Index),
+    (r_fj, r_idx): (&'a FlatJson, Index),
+    rows: &mut Vec<Row>,
+    statuses: &mut Vec<MergeStatus>,
+    parents: &mut Vec<OptionIndex>,
+    array_mode: ArrayMergeMode,
+) -> MergeStatus {
+    let l_row = &l_fj.0[l_idx];
+    let r_row = &r_fj.0[r_idx];
+
+    match (container_kind(&l_row.value), container_kind(&r_row.value)) {
+        (Some(ContainerType::Object), Some(ContainerType::Object)) => {
+            let left_children = l_fj.children_of(l_idx);
+            let right_children = r_fj.children_of(r_idx);
+
+            let mut keys = vec![];
+            let mut seen = std::collections::HashSet::new();
+            for &c in &left_children {
+                let key = l_fj.0[c].key.clone().unwrap();
+                if seen.insert(key.clone()) {
+                    keys.push(key);
+                }
+            }
+            for &c in &right_children {
+                let key = r_fj.0[c].key.clone().unwrap();
+                if seen.insert(key.clone()) {
+                    keys.push(key);
+                }
+            }
+
+            let items: Vec<MergeItem> = keys
+                .into_iter()
+                .map(|key| {
+                    let l_child = left_children
+                        .iter()
+                        .copied()
+                        .find(|&c| l_fj.0[c].key.as_deref() == Some(key.as_str()));
+                    let r_child = right_children
+                        .iter()
+                        .copied()
+                        .find(|&c| r_fj.0[c].key.as_deref() == Some(key.as_str()));
+                    (
+                        Some(key),
+                        l_child.map(|c| (l_fj, c)),
+                        r_child.map(|c| (r_fj, c)),
+                    )
+                })
+                .collect();
+
+            merge_rows(ContainerType::Object, rows, statuses, parents, items, array_mode)
+        }
+        (Some(ContainerType::Array), Some(ContainerType::Array)) => {
+            let left_children = l_fj.children_of(l_idx);
+            let right_children = r_fj.children_of(r_idx);
+
+            let items: Vec<MergeItem> = match array_mode {
+                ArrayMergeMode::Aligned => {
+                    let len = left_children.len().max(right_children.len());
+                    (0..len)
+                        .map(|i| {
+                            (
+                                None,
+                                left_children.get(i).map(|&c| (l_fj, c)),
+                                right_children.get(i).map(|&c| (r_fj, c)),
+                            )
+                        })
+                        .collect()
+                }
+                ArrayMergeMode::Concatenated => left_children
+                    .iter()
+                    .map(|&c| (None, Some((l_fj, c)), None))
+                    .chain(right_children.iter().map(|&c| (None, None, Some((r_fj, c)))))
+                    .collect(),
+            };
+
+            merge_rows(ContainerType::Array, rows, statuses, parents, items, array_mode)
+        }
+        (None, None) => {
+            let status = if l_row.value == r_row.value {
+                MergeStatus::Unchanged
+            } else {
+                MergeStatus::Modified
+            };
+
+            let depth = parents.len() - 1;
+            let parent = *parents.last().unwrap();
+            rows.push(Row {
+                parent,
+                prev_sibling: OptionIndex::Nil,
+                next_sibling: OptionIndex::Nil,
+                depth,
+                index: 0,
+                original_index: 0,
+                start_index: r_row.start_index,
+                end_index: r_row.end_index,
+                key: None,
+                value: r_row.value.clone(),
+            });
+            statuses.push(status);
+
+            status
+        }
+        // The two sides have different shapes (e.g. an object vs an array,
+        // or a container vs a scalar): show the right-hand value and mark
+        // this node `Modified`.
+        _ => {
+            let root_index = rows.len();
+            copy_subtree(r_fj, r_idx, rows, statuses, parents, MergeStatus::Added);
+            statuses[root_index] = MergeStatus::Modified;
+            MergeStatus::Modified
+        }
+    }
+}
+
+// Pushes one container's worth of already-paired-up `items` (each an
+// optional left row and optional right row, recursively merged), wiring up
+// the sibling chain/first_child/last_child exactly like `parse_container`
+// does. The container is `Modified` if any child is not `Unchanged`.
+fn merge_rows(
+    container_type: ContainerType,
+    rows: &mut Vec<Row>,
+    statuses: &mut Vec<MergeStatus>,
+    parents: &mut Vec<OptionIndex>,
+    items: Vec<MergeItem>,
+    array_mode: ArrayMergeMode,
+) -> MergeStatus {
+    let depth = parents.len() - 1;
+    let parent = *parents.last().unwrap();
+
+    if items.is_empty() {
+        rows.push(Row {
+            parent,
+            prev_sibling: OptionIndex::Nil,
+            next_sibling: OptionIndex::Nil,
+            depth,
+            index: 0,
+            original_index: 0,
+            start_index: 0,
+            end_index: 0,
+            key: None,
+            value: match container_type {
+                ContainerType::Object => Value::EmptyObject,
+                ContainerType::Array => Value::EmptyArray,
+            },
+        });
+        statuses.push(MergeStatus::Unchanged);
+        return MergeStatus::Unchanged;
+    }
+
+    let open_index = rows.len();
+    rows.push(Row {
+        parent,
+        prev_sibling: OptionIndex::Nil,
+        next_sibling: OptionIndex::Nil,
+        depth,
+        index: 0,
+        original_index: 0,
+        start_index: 0,
+        end_index: 0,
+        key: None,
+        value: Value::OpenContainer {
+            container_type,
+            first_child: open_index + 1,
+            close_index: 0,
+        },
+    });
+    // Placeholder; corrected once we know whether any child changed.
+    statuses.push(MergeStatus::Unchanged);
+
+    parents.push(OptionIndex::Index(open_index));
+
+    let mut prev_sibling = OptionIndex::Nil;
+    let mut last_child = open_index;
+    let mut any_changed = false;
+
+    for (i, (key, left, right)) in items.into_iter().enumerate() {
+        let child_index = rows.len();
+        let status = merge_node(left, right, rows, statuses, parents, array_mode);
+        any_changed |= status != MergeStatus::Unchanged;
+
+        rows[child_index].index = i;
+        rows[child_index].original_index = i;
+        rows[child_index].key = key;
+        rows[child_index].prev_sibling = prev_sibling;
+        if let OptionIndex::Index(p) = prev_sibling {
+            rows[p].next_sibling = OptionIndex::Index(child_index);
+        }
+        prev_sibling = OptionIndex::Index(child_index);
+        last_child = child_index;
+    }
+
+    parents.pop();
+
+    let close_index = rows.len();
+    rows.push(Row {
+        parent,
+        prev_sibling: OptionIndex::Nil,
+        next_sibling: OptionIndex::Nil,
+        depth,
+        index: 0,
+        original_index: 0,
+        start_index: 0,
+        end_index: 0,
+        key: None,
+        value: Value::CloseContainer {
+            container_type,
+            last_child,
+            open_index,
+        },
+    });
+
+    let container_status = if any_changed {
+        MergeStatus::Modified
+    } else {
+        MergeStatus::Unchanged
+    };
+    statuses.push(container_status);
+    statuses[open_index] = container_status;
+
+    if let Value::OpenContainer {
+        close_index: ref mut ci,
+        ..
+    } = &mut rows[open_index].value
+    {
+        *ci = close_index;
+    }
+
+    container_status
+}
+
+// One segment of a path expression like `store.books[*].price` or
+// `/^env_.*/[0]`. Object segments match a literal key or a `/regex/`;
+// array segments resolve an explicit index, a half-open range, or `*`.
+#[derive(Debug)]
+pub enum PathSegment {
+    Key(String),
+    KeyRegex(Regex),
+    Index(usize),
+    IndexRange(usize, usize),
+    Wildcard,
+}
+
+#[derive(Debug)]
+pub struct PathQueryError {
+    pub message: String,
+}
+
+// Parses a path expression into a sequence of `PathSegment`s, for later use
+// with `FlatJson::query`. Segments are separated by `.`; an array subscript
+// (`[0]`, `[1:3]`, or `[*]`) may directly follow a key segment.
+pub fn parse_path(expr: &str) -> Result<Vec<PathSegment>, PathQueryError> {
+    let mut segments = vec![];
+    let mut chars = expr.chars().peekable();
+
+    loop {
+        match chars.peek() {
+            Some('/') => {
+                chars.next();
+                let mut pattern = String::new();
+                let mut closed = false;
+                while let Some(c) = chars.next() {
+                    if c == '\\' {
+                        if let Some(escaped) = chars.next() {
+                            pattern.push(escaped);
+                        }
+                    } else if c == '/' {
+                        closed = true;
+                        break;
+                    } else {
+                        pattern.push(c);
+                    }
+                }
+                if !closed {
+                    return Err(PathQueryError {
+                        message: "unterminated /regex/ segment".to_owned(),
+                    });
+                }
+                let regex = Regex::new(&pattern).map_err(|e| PathQueryError {
+                    message: format!("invalid regex `{}`: {}", pattern, e),
+                })?;
+                segments.push(PathSegment::KeyRegex(regex));
+            }
+            Some('[') => {
+                // A bare array subscript with no preceding key, e.g. the
+                // whole expression is just `[1:3]`.
+            }
+            Some(_) => {
+                let mut key = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c == '.' || c == '[' {
+                        break;
+                    }
+                    key.push(c);
+                    chars.next();
+                }
+                if key.is_empty() {
+                    return Err(PathQueryError {
+                        message: "expected a key segment".to_owned(),
+                    });
+                }
+                segments.push(PathSegment::Key(key));
+            }
+            None => {
+                return Err(PathQueryError {
+                    message: "expected a path segment".to_owned(),
+                })
             }
         }
+
+        while chars.peek() == Some(&'[') {
+            chars.next();
+            let mut subscript = String::new();
+            let mut closed = false;
+            for c in chars.by_ref() {
+                if c == ']' {
+                    closed = true;
+                    break;
+                }
+                subscript.push(c);
+            }
+            if !closed {
+                return Err(PathQueryError {
+                    message: "unterminated [..] subscript".to_owned(),
+                });
+            }
+
+            if subscript == "*" {
+                segments.push(PathSegment::Wildcard);
+            } else if let Some((lo, hi)) = subscript.split_once(':') {
+                let lo: usize = lo.parse().map_err(|_| PathQueryError {
+                    message: format!("invalid range start in [{}]", subscript),
+                })?;
+                let hi: usize = hi.parse().map_err(|_| PathQueryError {
+                    message: format!("invalid range end in [{}]", subscript),
+                })?;
+                segments.push(PathSegment::IndexRange(lo, hi));
+            } else {
+                let index: usize = subscript.parse().map_err(|_| PathQueryError {
+                    message: format!("invalid array index [{}]", subscript),
+                })?;
+                segments.push(PathSegment::Index(index));
+            }
+        }
+
+        match chars.peek() {
+            Some('.') => {
+                chars.next();
+            }
+            Some(_) => {
+                return Err(PathQueryError {
+                    message: "expected `.` between path segments".to_owned(),
+                })
+            }
+            None => break,
+        }
+    }
+
+    Ok(segments)
+}
+
+impl FlatJson {
+    // Evaluates a parsed path expression from the root and returns the
+    // indexes of every matching row. `search` builds on this to let users
+    // jump between all nodes matching a path, and any matched index can be
+    // passed to `sort` as the container to sort. `merge` works on whole
+    // documents and takes no row index.
+    pub fn query(&self, path: &[PathSegment]) -> Vec<usize> {
+        self.query_from(0, path)
+    }
+
+    fn query_from(&self, current: Index, path: &[PathSegment]) -> Vec<Index> {
+        let (segment, rest) = match path.split_first() {
+            Some(parts) => parts,
+            None => return vec![current],
+        };
+
+        let children = self.children_of(current);
+        let matches: Vec<Index> = match segment {
+            PathSegment::Key(key) => children
+                .into_iter()
+                .filter(|&c| self.0[c].key.as_deref() == Some(key.as_str()))
+                .collect(),
+            PathSegment::KeyRegex(regex) => children
+                .into_iter()
+                .filter(|&c| self.0[c].key.as_deref().is_some_and(|k| regex.is_match(k)))
+                .collect(),
+            PathSegment::Index(index) => children
+                .into_iter()
+                .filter(|&c| self.0[c].index == *index)
+                .collect(),
+            PathSegment::IndexRange(lo, hi) => children
+                .into_iter()
+                .filter(|&c| self.0[c].index >= *lo && self.0[c].index < *hi)
+                .collect(),
+            PathSegment::Wildcard => children,
+        };
+
+        matches
+            .into_iter()
+            .flat_map(|c| self.query_from(c, rest))
+            .collect()
     }
 }
 
@@ -291,4 +1115,226 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_object_children_are_emitted_in_source_order() {
+        let json = r#"{"z": 1, "a": 2, "m": 3}"#;
+        let fj = parse_top_level_json(json.to_owned()).unwrap();
+
+        // Not alphabetical, not insertion-into-a-map order: exactly as written.
+        assert_eq!(child_keys(&fj, 0), vec!["z", "a", "m"]);
+    }
+
+    #[test]
+    fn test_raw_source_preserves_verbatim_formatting() {
+        let json = r#"{"pi": 3.140, "note": "a  b"}"#;
+        let fj = parse_top_level_json(json.to_owned()).unwrap();
+        let children = fj.children_of(0);
+
+        // Trailing zero and exact spacing survive, unlike re-serializing the
+        // parsed `Number`/`String` would guarantee.
+        assert_eq!(fj.raw_source(children[0]), "3.140");
+        assert_eq!(fj.raw_source(children[1]), r#""a  b""#);
+    }
+
+    #[test]
+    fn test_spans_cover_exact_source_text() {
+        let json = r#"{"a": 2, "b": [4, "5"]}"#;
+        let fj = parse_top_level_json(json.to_owned()).unwrap();
+
+        for row in &fj.0 {
+            assert!(row.start_index <= row.end_index);
+            assert!(row.end_index <= json.len());
+        }
+
+        // The top-level object spans the whole input.
+        assert_eq!((fj.0[0].start_index, fj.0[0].end_index), (0, json.len()));
+        // The "b" array spans from its `[` to its `]`.
+        let b_array = &fj.0[2];
+        assert_eq!(&json[b_array.start_index..b_array.end_index], r#"[4, "5"]"#);
+    }
+
+    fn child_keys(fj: &FlatJson, container: Index) -> Vec<String> {
+        let first_child = match &fj.0[container].value {
+            Value::OpenContainer { first_child, .. } => *first_child,
+            _ => panic!("row {} is not an open container", container),
+        };
+
+        let mut keys = vec![];
+        let mut next = OptionIndex::Index(first_child);
+        while let OptionIndex::Index(child) = next {
+            keys.push(fj.0[child].key.clone().unwrap());
+            next = fj.0[child].next_sibling;
+        }
+        keys
+    }
+
+    #[test]
+    fn test_sort_object_keys_and_restore_original_order() {
+        let json = r#"{"c": 1, "a": 2, "b": 3}"#;
+        let mut fj = parse_top_level_json(json.to_owned()).unwrap();
+
+        fj.sort(0, SortOrder::Ascending);
+        assert_eq!(child_keys(&fj, 0), vec!["a", "b", "c"]);
+
+        fj.sort(0, SortOrder::Descending);
+        assert_eq!(child_keys(&fj, 0), vec!["c", "b", "a"]);
+
+        fj.sort(0, SortOrder::Original);
+        assert_eq!(child_keys(&fj, 0), vec!["c", "a", "b"]);
+    }
+
+    #[test]
+    fn test_sort_is_recursive_and_preserves_links() {
+        let json = r#"{"outer": {"c": 1, "a": 2}}"#;
+        let mut fj = parse_top_level_json(json.to_owned()).unwrap();
+
+        fj.sort(0, SortOrder::Ascending);
+
+        let outer = match &fj.0[0].value {
+            Value::OpenContainer { first_child, .. } => *first_child,
+            _ => panic!("expected open container"),
+        };
+        assert_eq!(child_keys(&fj, outer), vec!["a", "c"]);
+
+        // first_child/last_child and the sibling chain all still agree.
+        let (first_child, close_index) = match &fj.0[outer].value {
+            Value::OpenContainer {
+                first_child,
+                close_index,
+                ..
+            } => (*first_child, *close_index),
+            _ => panic!("expected open container"),
+        };
+        assert_eq!(fj.0[first_child].prev_sibling, OptionIndex::Nil);
+        match &fj.0[close_index].value {
+            Value::CloseContainer { last_child, .. } => {
+                assert_eq!(fj.0[*last_child].next_sibling, OptionIndex::Nil);
+            }
+            _ => panic!("expected close container"),
+        }
+    }
+
+    fn child_statuses(fj: &FlatJson, statuses: &[MergeStatus], container: Index) -> Vec<(Option<String>, MergeStatus)> {
+        fj.children_of(container)
+            .into_iter()
+            .map(|c| (fj.0[c].key.clone(), statuses[c]))
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_objects_tags_added_removed_modified_unchanged() {
+        let left = parse_top_level_json(r#"{"a": 1, "b": 2, "c": 3}"#.to_owned()).unwrap();
+        let right = parse_top_level_json(r#"{"a": 1, "b": 9, "d": 4}"#.to_owned()).unwrap();
+
+        let (merged, statuses) = FlatJson::merge(&left, &right, ArrayMergeMode::Aligned);
+
+        assert_eq!(
+            child_statuses(&merged, &statuses, 0),
+            vec![
+                (Some("a".to_owned()), MergeStatus::Unchanged),
+                (Some("b".to_owned()), MergeStatus::Modified),
+                (Some("c".to_owned()), MergeStatus::Removed),
+                (Some("d".to_owned()), MergeStatus::Added),
+            ]
+        );
+        // A child changed, so the root object is Modified too.
+        assert_eq!(statuses[0], MergeStatus::Modified);
+    }
+
+    #[test]
+    fn test_merge_unchanged_objects_report_unchanged_root() {
+        let left = parse_top_level_json(r#"{"a": 1}"#.to_owned()).unwrap();
+        let right = parse_top_level_json(r#"{"a": 1}"#.to_owned()).unwrap();
+
+        let (_merged, statuses) = FlatJson::merge(&left, &right, ArrayMergeMode::Aligned);
+        assert_eq!(statuses[0], MergeStatus::Unchanged);
+    }
+
+    #[test]
+    fn test_merge_arrays_aligned_vs_concatenated() {
+        let left = parse_top_level_json(r#"[1, 2]"#.to_owned()).unwrap();
+        let right = parse_top_level_json(r#"[1, 9, 3]"#.to_owned()).unwrap();
+
+        let (aligned, aligned_statuses) = FlatJson::merge(&left, &right, ArrayMergeMode::Aligned);
+        assert_eq!(
+            child_statuses(&aligned, &aligned_statuses, 0)
+                .into_iter()
+                .map(|(_, s)| s)
+                .collect::<Vec<_>>(),
+            vec![
+                MergeStatus::Unchanged,
+                MergeStatus::Modified,
+                MergeStatus::Added,
+            ]
+        );
+
+        let (concatenated, concatenated_statuses) =
+            FlatJson::merge(&left, &right, ArrayMergeMode::Concatenated);
+        assert_eq!(
+            child_statuses(&concatenated, &concatenated_statuses, 0)
+                .into_iter()
+                .map(|(_, s)| s)
+                .collect::<Vec<_>>(),
+            vec![
+                MergeStatus::Removed,
+                MergeStatus::Removed,
+                MergeStatus::Added,
+                MergeStatus::Added,
+                MergeStatus::Added,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_query_path_with_wildcard_and_key() {
+        let json = r#"{"store": {"books": [{"price": 10}, {"price": 20}]}}"#;
+        let fj = parse_top_level_json(json.to_owned()).unwrap();
+
+        let path = parse_path("store.books[*].price").unwrap();
+        let matches = fj.query(&path);
+
+        let prices: Vec<i64> = matches
+            .into_iter()
+            .map(|i| match &fj.0[i].value {
+                Value::Number(n) => n.as_i64().unwrap(),
+                _ => panic!("expected a number"),
+            })
+            .collect();
+        assert_eq!(prices, vec![10, 20]);
+    }
+
+    #[test]
+    fn test_query_path_with_regex_key() {
+        let json = r#"{"env_a": 1, "env_b": 2, "other": 3}"#;
+        let fj = parse_top_level_json(json.to_owned()).unwrap();
+
+        let path = parse_path("/^env_.*/").unwrap();
+        let mut matches = fj.query(&path);
+        matches.sort();
+
+        let keys: Vec<String> = matches
+            .into_iter()
+            .map(|i| fj.0[i].key.clone().unwrap())
+            .collect();
+        assert_eq!(keys, vec!["env_a", "env_b"]);
+    }
+
+    #[test]
+    fn test_query_path_with_index_range() {
+        let json = r#"[10, 20, 30, 40]"#;
+        let fj = parse_top_level_json(json.to_owned()).unwrap();
+
+        let path = parse_path("[1:3]").unwrap();
+        let matches = fj.query(&path);
+
+        let values: Vec<i64> = matches
+            .into_iter()
+            .map(|i| match &fj.0[i].value {
+                Value::Number(n) => n.as_i64().unwrap(),
+                _ => panic!("expected a number"),
+            })
+            .collect();
+        assert_eq!(values, vec![20, 30]);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,314 @@
+// A hand-rolled JSON tokenizer used to drive `flatjson::parse_top_level_json`
+// directly, without first materializing a `serde_json::Value` tree. Each
+// token carries the byte range it was lexed from so callers can recover the
+// exact original source text (see `FlatJson::raw_source`).
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    ObjectOpen,
+    ObjectClose,
+    ArrayOpen,
+    ArrayClose,
+    Colon,
+    Comma,
+    Null,
+    True,
+    False,
+    Number,
+    String(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug)]
+pub struct TokenizeError {
+    pub message: String,
+    pub index: usize,
+}
+
+impl fmt::Display for TokenizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at byte {})", self.message, self.index)
+    }
+}
+
+pub struct JsonTokenizer<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JsonTokenizer<'a> {
+    pub fn new(json: &'a str) -> Self {
+        JsonTokenizer {
+            bytes: json.as_bytes(),
+            pos: 0,
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(&b) = self.bytes.get(self.pos) {
+            if b == b' ' || b == b'\t' || b == b'\n' || b == b'\r' {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn error(&self, message: &str) -> TokenizeError {
+        TokenizeError {
+            message: message.to_owned(),
+            index: self.pos,
+        }
+    }
+
+    fn expect(&mut self, literal: &str, kind: TokenKind) -> Result<Token, TokenizeError> {
+        let start = self.pos;
+        let end = start + literal.len();
+        if self.bytes.get(start..end) == Some(literal.as_bytes()) {
+            self.pos = end;
+            Ok(Token { kind, start, end })
+        } else {
+            Err(self.error(&format!("expected literal `{}`", literal)))
+        }
+    }
+
+    fn lex_string(&mut self) -> Result<Token, TokenizeError> {
+        let start = self.pos;
+        debug_assert_eq!(self.bytes[self.pos], b'"');
+        self.pos += 1;
+
+        let mut value = String::new();
+        loop {
+            match self.bytes.get(self.pos) {
+                None => return Err(self.error("unterminated string")),
+                Some(b'"') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    match self.bytes.get(self.pos) {
+                        Some(b'"') => value.push('"'),
+                        Some(b'\\') => value.push('\\'),
+                        Some(b'/') => value.push('/'),
+                        Some(b'n') => value.push('\n'),
+                        Some(b't') => value.push('\t'),
+                        Some(b'r') => value.push('\r'),
+                        Some(b'b') => value.push('\u{8}'),
+                        Some(b'f') => value.push('\u{c}'),
+                        Some(b'u') => {
+                            let hex = self
+                                .bytes
+                                .get(self.pos + 1..self.pos + 5)
+                                .and_then(|b| std::str::from_utf8(b).ok())
+                                .ok_or_else(|| self.error("invalid unicode escape"))?;
+                            let code = u32::from_str_radix(hex, 16)
+                                .map_err(|_| self.error("invalid unicode escape"))?;
+                            self.pos += 4;
+
+                            if (0xD800..=0xDBFF).contains(&code) {
+                                // High surrogate: the next escape must be a low
+                                // surrogate so the pair can be combined into one
+                                // scalar value.
+                                let is_low_escape = self.bytes.get(self.pos + 1) == Some(&b'\\')
+                                    && self.bytes.get(self.pos + 2) == Some(&b'u');
+                                if !is_low_escape {
+                                    return Err(self.error("expected low surrogate pair"));
+                                }
+                                let low_hex = self
+                                    .bytes
+                                    .get(self.pos + 3..self.pos + 7)
+                                    .and_then(|b| std::str::from_utf8(b).ok())
+                                    .ok_or_else(|| self.error("invalid unicode escape"))?;
+                                let low = u32::from_str_radix(low_hex, 16)
+                                    .map_err(|_| self.error("invalid unicode escape"))?;
+                                if !(0xDC00..=0xDFFF).contains(&low) {
+                                    return Err(self.error("invalid low surrogate"));
+                                }
+                                let combined =
+                                    0x10000 + (code - 0xD800) * 0x400 + (low - 0xDC00);
+                                value.push(char::from_u32(combined).unwrap_or('\u{fffd}'));
+                                self.pos += 6;
+                            } else {
+                                value.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                            }
+                        }
+                        _ => return Err(self.error("invalid escape sequence")),
+                    }
+                    self.pos += 1;
+                }
+                Some(_) => {
+                    let ch_start = self.pos;
+                    let ch_len = utf8_char_width(self.bytes[ch_start]);
+                    let s = std::str::from_utf8(&self.bytes[ch_start..ch_start + ch_len])
+                        .map_err(|_| self.error("invalid utf-8"))?;
+                    value.push_str(s);
+                    self.pos += ch_len;
+                }
+            }
+        }
+
+        Ok(Token {
+            kind: TokenKind::String(value),
+            start,
+            end: self.pos,
+        })
+    }
+
+    fn lex_number(&mut self) -> Result<Token, TokenizeError> {
+        let start = self.pos;
+        if self.bytes.get(self.pos) == Some(&b'-') {
+            self.pos += 1;
+        }
+        while matches!(self.bytes.get(self.pos), Some(b'0'..=b'9')) {
+            self.pos += 1;
+        }
+        if self.bytes.get(self.pos) == Some(&b'.') {
+            self.pos += 1;
+            while matches!(self.bytes.get(self.pos), Some(b'0'..=b'9')) {
+                self.pos += 1;
+            }
+        }
+        if matches!(self.bytes.get(self.pos), Some(b'e') | Some(b'E')) {
+            self.pos += 1;
+            if matches!(self.bytes.get(self.pos), Some(b'+') | Some(b'-')) {
+                self.pos += 1;
+            }
+            while matches!(self.bytes.get(self.pos), Some(b'0'..=b'9')) {
+                self.pos += 1;
+            }
+        }
+
+        if self.pos == start {
+            return Err(self.error("invalid number"));
+        }
+
+        Ok(Token {
+            kind: TokenKind::Number,
+            start,
+            end: self.pos,
+        })
+    }
+
+    pub fn next_token(&mut self) -> Result<Option<Token>, TokenizeError> {
+        self.skip_whitespace();
+
+        let start = self.pos;
+        let token = match self.bytes.get(start) {
+            None => return Ok(None),
+            Some(b'{') => {
+                self.pos += 1;
+                Token {
+                    kind: TokenKind::ObjectOpen,
+                    start,
+                    end: self.pos,
+                }
+            }
+            Some(b'}') => {
+                self.pos += 1;
+                Token {
+                    kind: TokenKind::ObjectClose,
+                    start,
+                    end: self.pos,
+                }
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                Token {
+                    kind: TokenKind::ArrayOpen,
+                    start,
+                    end: self.pos,
+                }
+            }
+            Some(b']') => {
+                self.pos += 1;
+                Token {
+                    kind: TokenKind::ArrayClose,
+                    start,
+                    end: self.pos,
+                }
+            }
+            Some(b':') => {
+                self.pos += 1;
+                Token {
+                    kind: TokenKind::Colon,
+                    start,
+                    end: self.pos,
+                }
+            }
+            Some(b',') => {
+                self.pos += 1;
+                Token {
+                    kind: TokenKind::Comma,
+                    start,
+                    end: self.pos,
+                }
+            }
+            Some(b'"') => self.lex_string()?,
+            Some(b'n') => self.expect("null", TokenKind::Null)?,
+            Some(b't') => self.expect("true", TokenKind::True)?,
+            Some(b'f') => self.expect("false", TokenKind::False)?,
+            Some(b'-') | Some(b'0'..=b'9') => self.lex_number()?,
+            Some(_) => return Err(self.error("unexpected character")),
+        };
+
+        Ok(Some(token))
+    }
+}
+
+fn utf8_char_width(first_byte: u8) -> usize {
+    if first_byte < 0x80 {
+        1
+    } else if first_byte >> 5 == 0b110 {
+        2
+    } else if first_byte >> 4 == 0b1110 {
+        3
+    } else {
+        4
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokenize(json: &str) -> Vec<TokenKind> {
+        let mut tokenizer = JsonTokenizer::new(json);
+        let mut kinds = vec![];
+        while let Some(token) = tokenizer.next_token().unwrap() {
+            kinds.push(token.kind);
+        }
+        kinds
+    }
+
+    #[test]
+    fn test_tokenize_scalars() {
+        assert_eq!(
+            tokenize(r#"null true false 12.5e1 "hi""#),
+            vec![
+                TokenKind::Null,
+                TokenKind::True,
+                TokenKind::False,
+                TokenKind::Number,
+                TokenKind::String("hi".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_token_spans() {
+        let mut tokenizer = JsonTokenizer::new(r#"{"a": 1}"#);
+        let open = tokenizer.next_token().unwrap().unwrap();
+        assert_eq!((open.start, open.end), (0, 1));
+        let key = tokenizer.next_token().unwrap().unwrap();
+        assert_eq!((key.start, key.end), (1, 4));
+    }
+}
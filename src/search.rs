@@ -0,0 +1,8 @@
+// Lets users jump between nodes in a `FlatJson` by path expression.
+
+use crate::flatjson::{parse_path, FlatJson, PathQueryError};
+
+pub fn find_by_path(flatjson: &FlatJson, path_expression: &str) -> Result<Vec<usize>, PathQueryError> {
+    let path = parse_path(path_expression)?;
+    Ok(flatjson.query(&path))
+}
@@ -0,0 +1,9 @@
+// Commands the interactive viewer dispatches keybindings to.
+
+use crate::flatjson::FlatJson;
+
+// The "yank" command: returns the node's verbatim original text. It does not
+// itself write to a clipboard.
+pub fn yank_raw_source(flatjson: &FlatJson, index: usize) -> &str {
+    flatjson.raw_source(index)
+}